@@ -0,0 +1,218 @@
+//! Enumeration and inspection of the system's audio devices, independent of any particular
+//! **AudioUnit**.
+//!
+//! See [**AudioUnit::set_device**](../struct.AudioUnit.html#method.set_device) for binding an
+//! **AudioUnit** to one of the devices listed here.
+
+use bindings::audio_unit as au;
+use error::Error;
+use libc;
+use std::ffi::CStr;
+use std::mem;
+use std::ptr;
+use super::Scope;
+
+
+/// A unique identifier for an audio device known to the system, as returned by
+/// [**audio_device_ids**](./fn.audio_device_ids.html).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AudioDeviceID(pub au::AudioDeviceID);
+
+
+fn audio_object_get_property_data_size(id: au::AudioObjectID,
+                                        address: &au::AudioObjectPropertyAddress)
+    -> Result<u32, Error>
+{
+    let mut size: u32 = 0;
+    unsafe {
+        let status = au::AudioObjectGetPropertyDataSize(
+            id, address as *const _, 0, ptr::null(), &mut size as *mut _);
+        try!(Error::from_os_status(status));
+    }
+    Ok(size)
+}
+
+fn audio_object_get_property_data<T>(id: au::AudioObjectID,
+                                      address: &au::AudioObjectPropertyAddress)
+    -> Result<T, Error>
+{
+    let mut size = mem::size_of::<T>() as u32;
+    unsafe {
+        let mut data: T = mem::uninitialized();
+        let data_ptr = &mut data as *mut _ as *mut libc::c_void;
+        let status = au::AudioObjectGetPropertyData(
+            id, address as *const _, 0, ptr::null(), &mut size as *mut _, data_ptr);
+        try!(Error::from_os_status(status));
+        Ok(data)
+    }
+}
+
+fn audio_object_get_property_array<T>(id: au::AudioObjectID,
+                                       address: &au::AudioObjectPropertyAddress)
+    -> Result<Vec<T>, Error>
+{
+    let size = try!(audio_object_get_property_data_size(id, address));
+    let (len, byte_len) = super::clamped_vec_len::<T>(size);
+    let mut data: Vec<T> = Vec::with_capacity(len);
+    unsafe {
+        let mut out_size = byte_len as u32;
+        let data_ptr = data.as_mut_ptr() as *mut libc::c_void;
+        let status = au::AudioObjectGetPropertyData(
+            id, address as *const _, 0, ptr::null(), &mut out_size as *mut _, data_ptr);
+        try!(Error::from_os_status(status));
+        data.set_len(len);
+    }
+    Ok(data)
+}
+
+/// Decode a `CFStringRef` into a Rust `String`, releasing the `CFStringRef` in the process.
+fn cfstring_to_string(string_ref: au::CFStringRef) -> String {
+    unsafe {
+        let length = au::CFStringGetLength(string_ref);
+        let max_size =
+            au::CFStringGetMaximumSizeForEncoding(length, au::kCFStringEncodingUTF8) + 1;
+        let mut buffer: Vec<u8> = vec![0; max_size as usize];
+        au::CFStringGetCString(
+            string_ref, buffer.as_mut_ptr() as *mut libc::c_char, max_size, au::kCFStringEncodingUTF8);
+        au::CFRelease(string_ref as au::CFTypeRef);
+        CStr::from_ptr(buffer.as_ptr() as *const libc::c_char).to_string_lossy().into_owned()
+    }
+}
+
+/// Enumerate the `AudioDeviceID`s of every audio device currently known to the system.
+pub fn audio_device_ids() -> Result<Vec<AudioDeviceID>, Error> {
+    let address = au::AudioObjectPropertyAddress {
+        mSelector: au::kAudioHardwarePropertyDevices,
+        mScope: au::kAudioObjectPropertyScopeGlobal,
+        mElement: au::kAudioObjectPropertyElementMaster,
+    };
+    let ids: Vec<au::AudioDeviceID> =
+        try!(audio_object_get_property_array(au::kAudioObjectSystemObject, &address));
+    Ok(ids.into_iter().map(AudioDeviceID).collect())
+}
+
+/// Retrieve the human-readable name of the given device.
+pub fn device_name(device: AudioDeviceID) -> Result<String, Error> {
+    let address = au::AudioObjectPropertyAddress {
+        mSelector: au::kAudioDevicePropertyDeviceNameCFString,
+        mScope: au::kAudioObjectPropertyScopeGlobal,
+        mElement: au::kAudioObjectPropertyElementMaster,
+    };
+    let string_ref: au::CFStringRef = try!(audio_object_get_property_data(device.0, &address));
+    Ok(cfstring_to_string(string_ref))
+}
+
+/// Retrieve the nominal sample rates supported by the given device.
+pub fn device_supported_sample_rates(device: AudioDeviceID) -> Result<Vec<au::AudioValueRange>, Error> {
+    let address = au::AudioObjectPropertyAddress {
+        mSelector: au::kAudioDevicePropertyAvailableNominalSampleRates,
+        mScope: au::kAudioObjectPropertyScopeGlobal,
+        mElement: au::kAudioObjectPropertyElementMaster,
+    };
+    audio_object_get_property_array(device.0, &address)
+}
+
+/// A type representing a property-change listener callback.
+///
+/// Invoked (with no arguments, as a simple change notification) whenever one of the addresses
+/// the owning [**PropertyListener**](./struct.PropertyListener.html) was registered for changes.
+pub type PropertyListenerCallback = FnMut();
+
+
+/// A handle to a property-change listener registered via
+/// [**add_property_listener**](./fn.add_property_listener.html).
+///
+/// The listener is unregistered and the boxed callback is dropped when this is dropped.
+pub struct PropertyListener {
+    object_id: au::AudioObjectID,
+    address: au::AudioObjectPropertyAddress,
+    callback_ptr: *mut libc::c_void,
+}
+
+/// Register `callback` to be invoked whenever the property identified by `selector`/`scope`
+/// changes on `object_id` (e.g. `kAudioObjectSystemObject` for default-device changes, or a
+/// specific device's ID for `kAudioDevicePropertyDeviceIsAlive`/nominal sample-rate changes).
+///
+/// This mirrors the `maybe_callback` ownership pattern used for the `AudioUnit` render callback:
+/// the boxed closure is leaked into a raw pointer so that it may be passed through as the
+/// listener's `inClientData`, and is reclaimed and dropped when the returned
+/// **PropertyListener** is dropped.
+pub fn add_property_listener(object_id: au::AudioObjectID,
+                              selector: au::AudioObjectPropertySelector,
+                              scope: au::AudioObjectPropertyScope,
+                              callback: Box<PropertyListenerCallback>)
+    -> Result<PropertyListener, Error>
+{
+    let address = au::AudioObjectPropertyAddress {
+        mSelector: selector,
+        mScope: scope,
+        mElement: au::kAudioObjectPropertyElementMaster,
+    };
+    let callback_ptr = Box::into_raw(Box::new(callback)) as *mut libc::c_void;
+    unsafe {
+        let status = au::AudioObjectAddPropertyListener(
+            object_id, &address as *const _, Some(property_listener_proc), callback_ptr);
+        if let Err(err) = Error::from_os_status(status) {
+            // Reclaim and drop the callback before bubbling up the error, rather than leaking it.
+            let _: Box<Box<PropertyListenerCallback>> =
+                Box::from_raw(callback_ptr as *mut Box<PropertyListenerCallback>);
+            return Err(err);
+        }
+    }
+    Ok(PropertyListener { object_id: object_id, address: address, callback_ptr: callback_ptr })
+}
+
+impl Drop for PropertyListener {
+    fn drop(&mut self) {
+        unsafe {
+            au::AudioObjectRemovePropertyListener(
+                self.object_id, &self.address as *const _, Some(property_listener_proc), self.callback_ptr);
+            let _: Box<Box<PropertyListenerCallback>> =
+                Box::from_raw(self.callback_ptr as *mut Box<PropertyListenerCallback>);
+        }
+    }
+}
+
+extern "C" fn property_listener_proc(_object_id: au::AudioObjectID,
+                                     _in_number_addresses: au::UInt32,
+                                     _in_addresses: *const au::AudioObjectPropertyAddress,
+                                     in_client_data: *mut libc::c_void) -> au::OSStatus {
+    let callback: *mut Box<PropertyListenerCallback> = in_client_data as *mut _;
+    unsafe {
+        (*callback)();
+    }
+    0 as au::OSStatus
+}
+
+
+/// Retrieve the number of channels the given device is configured with on the given scope
+/// (`Scope::Input` for the device's input stream, `Scope::Output` for its output stream).
+pub fn device_channels(device: AudioDeviceID, scope: Scope) -> Result<u32, Error> {
+    let device_scope = match scope {
+        Scope::Input => au::kAudioObjectPropertyScopeInput,
+        Scope::Output => au::kAudioObjectPropertyScopeOutput,
+        Scope::Global => au::kAudioObjectPropertyScopeGlobal,
+    };
+    let address = au::AudioObjectPropertyAddress {
+        mSelector: au::kAudioDevicePropertyStreamConfiguration,
+        mScope: device_scope,
+        mElement: au::kAudioObjectPropertyElementMaster,
+    };
+    let size = try!(audio_object_get_property_data_size(device.0, &address));
+    let mut bytes: Vec<u8> = vec![0; size as usize];
+    unsafe {
+        let mut out_size = size;
+        let buffer_list_ptr = bytes.as_mut_ptr() as *mut au::AudioBufferList;
+        let status = au::AudioObjectGetPropertyData(
+            device.0, &address as *const _, 0, ptr::null(), &mut out_size as *mut _,
+            buffer_list_ptr as *mut libc::c_void);
+        try!(Error::from_os_status(status));
+        let num_buffers = (*buffer_list_ptr).mNumberBuffers as usize;
+        let buffers_ptr = (*buffer_list_ptr).mBuffers.as_ptr();
+        let mut total_channels = 0;
+        for i in 0..num_buffers {
+            total_channels += (*buffers_ptr.offset(i as isize)).mNumberChannels;
+        }
+        Ok(total_channels)
+    }
+}
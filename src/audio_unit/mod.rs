@@ -22,7 +22,7 @@
 use bindings::audio_unit as au;
 use error::{Error, AudioUnitError};
 use libc;
-use self::stream_format::StreamFormat;
+use self::stream_format::{LinearPcmFlags, StreamFormat};
 use std::mem;
 use std::ptr;
 
@@ -38,37 +38,91 @@ pub use self::types::{
 
 
 pub mod audio_format;
+pub mod device;
+pub mod sample_format;
 pub mod stream_format;
 pub mod types;
 
+pub use self::device::AudioDeviceID;
+pub use self::sample_format::Sample;
 
-/// The input and output **Scope**s.
+
+/// The **Scope**s an **AudioUnit** property or parameter may apply to.
+///
+/// `Global` addresses the unit as a whole (e.g. properties like `CurrentDevice` that have no
+/// per-element meaning); `Input` and `Output` address a specific element's input or output side
+/// (see [**Element**](./enum.Element.html) for selecting which element).
 ///
 /// More info [here](https://developer.apple.com/library/ios/documentation/AudioUnit/Reference/AudioUnitPropertiesReference/index.html#//apple_ref/doc/constant_group/Audio_Unit_Scopes)
 /// and [here](https://developer.apple.com/library/mac/documentation/MusicAudio/Conceptual/AudioUnitProgrammingGuide/TheAudioUnit/TheAudioUnit.html).
 #[derive(Copy, Clone, Debug)]
 pub enum Scope {
-    Output = 0,
+    Global = 0,
     Input  = 1,
+    Output = 2,
 }
 
-/// Represents the **Input** and **Output** **Element**s.
+/// Represents the **Element** (aka "bus") of a **Scope** that we're addressing.
 ///
-/// These are used when specifying which **Element** we're setting the properties of.
+/// `Output` and `Input` are convenience aliases for the common I/O unit elements (bus 0 and bus
+/// 1 respectively); `Bus` allows addressing an arbitrary element, e.g. one of a mixer unit's
+/// many input buses.
 #[derive(Copy, Clone, Debug)]
 pub enum Element {
-    Output = 0,
-    Input  = 1,
+    Output,
+    Input,
+    Bus(u32),
+}
+
+impl Element {
+    /// The underlying element/bus number used by the CoreAudio APIs.
+    fn to_bus_number(self) -> u32 {
+        match self {
+            Element::Output => 0,
+            Element::Input => 1,
+            Element::Bus(n) => n,
+        }
+    }
 }
 
 
 /// The number of frames available in some buffer.
 pub type NumFrames = usize;
 
+/// The sample buffer(s) passed to a render or input callback.
+///
+/// An I/O unit may present its samples either **interleaved** (a single buffer holding
+/// `frames * channels` samples, frame by frame) or **planar**/non-interleaved (one buffer per
+/// channel, each of length `frames`). For a render callback, the layout is determined at render
+/// time by comparing the unit's `channels_per_frame` (from its `StreamFormat`) against the number
+/// of `AudioBuffer`s handed to us: more channels than buffers means the channels are packed into
+/// a single interleaved buffer. For an input callback, it is determined when the callback is set,
+/// from the `IS_NON_INTERLEAVED` flag of the unit's `StreamFormat`.
+pub enum Args<'b, S: 'b> {
+    /// A single buffer of `frames * channels` samples, frame by frame.
+    Interleaved(&'b mut [S]),
+    /// One buffer per channel, each of length `frames`.
+    NonInterleaved(Vec<&'b mut [S]>),
+}
+
 /// A type representing a render callback (aka "Input Procedure")
 /// If set on an AudioUnit, this will be called every time the AudioUnit requests audio.
-/// The first arg is [frames[channels]]; the second is the number of frames to render.
-pub type RenderCallback = FnMut(&mut[&mut[f32]], NumFrames) -> Result<(), String>;
+/// The first arg is the rendered sample buffer(s); the second is the number of frames to render.
+///
+/// `S` must match the sample type of the **AudioUnit**'s current
+/// [**StreamFormat**](./stream_format/struct.StreamFormat.html).
+pub type RenderCallback<S> = for<'b> FnMut(Args<'b, S>, NumFrames) -> Result<(), String>;
+
+
+/// A type representing an input callback (aka "Input Procedure").
+///
+/// If set on an AudioUnit, this will be called every time the AudioUnit has captured some
+/// audio. The first arg is the captured sample buffer(s); the second is the number of captured
+/// frames.
+///
+/// `S` must match the sample type of the **AudioUnit**'s current
+/// [**StreamFormat**](./stream_format/struct.StreamFormat.html).
+pub type InputCallback<S> = for<'b> FnMut(Args<'b, S>, NumFrames) -> Result<(), String>;
 
 
 /// A rust representation of the au::AudioUnit, including a pointer to the current rendering callback.
@@ -76,7 +130,32 @@ pub type RenderCallback = FnMut(&mut[&mut[f32]], NumFrames) -> Result<(), String
 /// Find the original Audio Unit Programming Guide [here](https://developer.apple.com/library/mac/documentation/MusicAudio/Conceptual/AudioUnitProgrammingGuide/TheAudioUnit/TheAudioUnit.html).
 pub struct AudioUnit {
     instance: au::AudioUnit,
-    maybe_callback: Option<*mut libc::c_void>
+    maybe_callback: Option<(*mut libc::c_void, unsafe fn(*mut libc::c_void))>,
+    maybe_input_callback: Option<(*mut libc::c_void, unsafe fn(*mut libc::c_void))>,
+    init: bool,
+}
+
+
+/// The data passed to the render procedure via its `inputProcRefCon` pointer.
+///
+/// `channels_per_frame` is captured up front (from the unit's `StreamFormat`) so the trampoline
+/// can tell interleaved buffers from planar ones without re-querying the format on every call.
+struct RenderProcData<S> {
+    channels_per_frame: usize,
+    callback: Box<RenderCallback<S>>,
+}
+
+/// The data passed to the input render procedure via its `inputProcRefCon` pointer.
+///
+/// This bundles the `AudioUnit` instance (so that the trampoline can pull captured frames via
+/// `AudioUnitRender`) alongside the boxed user callback. `interleaved` is captured up front (from
+/// the unit's `StreamFormat`) so the trampoline can give `AudioUnitRender` a buffer list shaped
+/// the way the negotiated format actually expects, rather than always assuming planar.
+struct InputProcData<S> {
+    unit: au::AudioUnit,
+    channels_per_frame: usize,
+    interleaved: bool,
+    callback: Box<InputCallback<S>>,
 }
 
 
@@ -146,15 +225,54 @@ impl AudioUnit {
                 au::AudioComponentInstanceNew(component, &mut instance as *mut au::AudioUnit)
             );
 
-            // Initialise the audio unit!
-            try_os_status!(au::AudioUnitInitialize(instance));
+            // Note: the audio unit is *not* initialised here. This gives the caller a chance to
+            // configure things that must be set before initialisation (e.g. `enable_io`) before
+            // it is initialised implicitly the first time `start` is called.
             Ok(AudioUnit {
                 instance: instance,
-                maybe_callback: None
+                maybe_callback: None,
+                maybe_input_callback: None,
+                init: false,
             })
         }
     }
 
+    /// Initialise the **AudioUnit** if it has not been initialised already.
+    ///
+    /// This is called automatically by [**start**](./struct.AudioUnit#method.start), but it may
+    /// also be called manually in order to query properties that require the unit to be
+    /// initialised beforehand.
+    pub fn initialize(&mut self) -> Result<(), Error> {
+        if !self.init {
+            unsafe { try_os_status!(au::AudioUnitInitialize(self.instance)); }
+            self.init = true;
+        }
+        Ok(())
+    }
+
+    /// Enable or disable input or output on the given **Scope**.
+    ///
+    /// For I/O units, `Scope::Input` addresses the input element (bus 1, used for
+    /// microphone/line-in capture) and `Scope::Output` addresses the output element (bus 0).
+    /// `Scope::Global` is not meaningful here and is treated as `Scope::Output`.
+    ///
+    /// This must be called before the **AudioUnit** is initialized (i.e. before
+    /// [**start**](./struct.AudioUnit#method.start) or
+    /// [**initialize**](./struct.AudioUnit#method.initialize) is called) in order to take
+    /// effect.
+    pub fn enable_io(&mut self, scope: Scope, enable: bool) -> Result<(), Error> {
+        let (scope, elem) = match scope {
+            Scope::Input => (Scope::Input, Element::Input),
+            Scope::Output | Scope::Global => (Scope::Output, Element::Output),
+        };
+        let enable: u32 = if enable { 1 } else { 0 };
+        // Fix: `kAudioOutputUnitProperty_EnableIO` must be set on `Scope::Output`/`Scope::Input`
+        // specifically, never `Scope::Global`. Before `Scope` grew an explicit `Global` variant,
+        // `Output` shared `Global`'s discriminant (0), so `enable_io(Scope::Output, ..)` was
+        // silently sending `Global` here; it now correctly sends `Output`.
+        self.set_property(au::kAudioOutputUnitProperty_EnableIO, scope, elem, Some(&enable))
+    }
+
     /// Sets the value for some property of the **AudioUnit**.
     ///
     /// To clear an audio unit property value, set the data paramater with `None::<()>`.
@@ -180,7 +298,7 @@ impl AudioUnit {
             (ptr, size)
         }).unwrap_or_else(|| (::std::ptr::null(), 0));
         let scope = scope as libc::c_uint;
-        let elem = elem as libc::c_uint;
+        let elem = elem.to_bus_number() as libc::c_uint;
         unsafe {
             try_os_status!(au::AudioUnitSetProperty(self.instance, id, scope, elem, data_ptr, size))
         }
@@ -198,7 +316,7 @@ impl AudioUnit {
     /// - **elem**: The audio unit element for the property.
     pub fn get_property<T>(&self, id: u32, scope: Scope, elem: Element) -> Result<T, Error> {
         let scope = scope as libc::c_uint;
-        let elem = elem as libc::c_uint;
+        let elem = elem.to_bus_number() as libc::c_uint;
         let mut size = ::std::mem::size_of::<T>() as u32;
         unsafe {
             let mut data: T = ::std::mem::uninitialized();
@@ -211,21 +329,126 @@ impl AudioUnit {
         }
     }
 
+    /// Get information about a property without retrieving its value: the size of the property's
+    /// data (in bytes) and whether or not it can be set.
+    ///
+    /// This is necessary for properties whose value is variable-length (e.g. arrays), where the
+    /// caller cannot know the size of the data up front as
+    /// [**get_property**](./struct.AudioUnit#method.get_property) assumes.
+    pub fn get_property_info(&self, id: u32, scope: Scope, elem: Element)
+        -> Result<(u32, bool), Error>
+    {
+        let scope = scope as libc::c_uint;
+        let elem = elem.to_bus_number() as libc::c_uint;
+        let mut size: u32 = 0;
+        let mut writable: au::Boolean = 0;
+        unsafe {
+            try_os_status!(au::AudioUnitGetPropertyInfo(
+                self.instance, id, scope, elem, &mut size as *mut _, &mut writable as *mut _));
+        }
+        Ok((size, writable != 0))
+    }
+
+    /// Get the value of a variable-length, array-valued property.
+    ///
+    /// The size of the property's data is first queried via
+    /// [**get_property_info**](./struct.AudioUnit#method.get_property_info), and a `Vec<T>` of
+    /// the appropriate length is then filled via `AudioUnitGetProperty`.
+    pub fn get_property_array<T>(&self, id: u32, scope: Scope, elem: Element)
+        -> Result<Vec<T>, Error>
+    {
+        let (size, _) = try!(self.get_property_info(id, scope, elem));
+        let (len, byte_len) = clamped_vec_len::<T>(size);
+        let scope_c = scope as libc::c_uint;
+        let elem_c = elem.to_bus_number() as libc::c_uint;
+        let mut data: Vec<T> = Vec::with_capacity(len);
+        unsafe {
+            let mut out_size = byte_len as u32;
+            let data_ptr = data.as_mut_ptr() as *mut libc::c_void;
+            try_os_status!(au::AudioUnitGetProperty(
+                self.instance, id, scope_c, elem_c, data_ptr, &mut out_size as *mut _));
+            data.set_len(len);
+        }
+        Ok(data)
+    }
+
+    /// The channel layout tags supported by the **AudioUnit**.
+    pub fn supported_channel_layouts(&self) -> Result<Vec<au::AudioChannelLayoutTag>, Error> {
+        let id = au::kAudioUnitProperty_SupportedChannelLayoutTags;
+        // Fix: same `Scope::Output` discriminant fix as `stream_format` below -- this was
+        // silently querying the global scope instead of the output scope before `Scope` grew an
+        // explicit `Global` variant.
+        self.get_property_array(id, Scope::Output, Element::Output)
+    }
+
+    /// The IDs of the parameters exposed by the **AudioUnit** on the given scope.
+    ///
+    /// See [**get_parameter**](./struct.AudioUnit#method.get_parameter) and
+    /// [**set_parameter**](./struct.AudioUnit#method.set_parameter) for reading and writing the
+    /// values of the returned parameters.
+    pub fn parameter_list(&self, scope: Scope) -> Result<Vec<au::AudioUnitParameterID>, Error> {
+        let id = au::kAudioUnitProperty_ParameterList;
+        self.get_property_array(id, scope, Element::Output)
+    }
+
+    /// Get the current value of a parameter (e.g. gain, pan, frequency) exposed by the
+    /// **AudioUnit**.
+    ///
+    /// See [**parameter_list**](./struct.AudioUnit#method.parameter_list) for discovering the
+    /// IDs of the parameters available on a given scope.
+    pub fn get_parameter(&self, id: u32, scope: Scope, elem: u32) -> Result<f32, Error> {
+        let scope = scope as libc::c_uint;
+        unsafe {
+            let mut value: f32 = mem::uninitialized();
+            try_os_status!(au::AudioUnitGetParameter(self.instance, id, scope, elem, &mut value as *mut _));
+            Ok(value)
+        }
+    }
+
+    /// Set the value of a parameter (e.g. gain, pan, frequency) exposed by the **AudioUnit**.
+    ///
+    /// `buffer_offset_in_frames` allows the new value to be scheduled to take effect part way
+    /// through the next render cycle, for use within a render callback; pass `0` to apply the
+    /// value immediately.
+    pub fn set_parameter(&mut self, id: u32, scope: Scope, elem: u32, value: f32,
+                         buffer_offset_in_frames: u32) -> Result<(), Error>
+    {
+        let scope = scope as libc::c_uint;
+        unsafe {
+            try_os_status!(au::AudioUnitSetParameter(
+                self.instance, id, scope, elem, value, buffer_offset_in_frames));
+        }
+        Ok(())
+    }
+
     /// Pass a render callback (aka "Input Procedure") to the **AudioUnit**.
-    pub fn set_render_callback(&mut self, f: Option<Box<RenderCallback>>) -> Result<(), Error> {
+    ///
+    /// `S` must match the sample type of the **AudioUnit**'s current
+    /// [**StreamFormat**](./stream_format/struct.StreamFormat.html); returns
+    /// `Error::WrongSampleFormat` if it does not.
+    pub fn set_render_callback<S>(&mut self, f: Option<Box<RenderCallback<S>>>) -> Result<(), Error>
+        where S: Sample,
+    {
         // Setup render callback. Notice that we relinquish ownership of the Callback
         // here so that it can be used as the C render callback via a void pointer.
         // We do however store the *mut so that we can convert back to a
-        // Box<Box<RenderCallback>> within our AudioUnit's Drop implementation
-        // (otherwise it would leak). The double-boxing is due to incompleteness with
-        // Rust's FnMut implemetation and is necessary to be able to convert to the
-        // correct pointer size.
+        // Box<RenderProcData<S>> within our AudioUnit's Drop implementation
+        // (otherwise it would leak).
         let callback_ptr = match f {
-            Some(x) => Box::into_raw(Box::new(x)) as *mut libc::c_void,
-            _ => ptr::null_mut()
+            Some(callback) => {
+                let format = try!(self.stream_format());
+                try!(check_sample_format::<S>(&format));
+                let channels_per_frame = format.channels_per_frame as usize;
+                let data = RenderProcData {
+                    channels_per_frame: channels_per_frame,
+                    callback: callback,
+                };
+                Box::into_raw(Box::new(data)) as *mut libc::c_void
+            },
+            None => ptr::null_mut(),
         };
         let render_callback = au::AURenderCallbackStruct {
-            inputProc: Some(input_proc),
+            inputProc: Some(render_proc::<S>),
             inputProcRefCon: callback_ptr
         };
 
@@ -235,18 +458,83 @@ impl AudioUnit {
                                Some(&render_callback)));
 
         self.free_render_callback();
-        self.maybe_callback = if !callback_ptr.is_null() { Some(callback_ptr) } else { None };
+        self.maybe_callback = if !callback_ptr.is_null() {
+            Some((callback_ptr, drop_render_proc_data::<S> as unsafe fn(*mut libc::c_void)))
+        } else {
+            None
+        };
         Ok(())
     }
 
     /// Retrieves ownership over the render callback and drops it.
     fn free_render_callback(&mut self) {
-        if let Some(callback) = self.maybe_callback.take() {
+        if let Some((ptr, drop_fn)) = self.maybe_callback.take() {
             // Here, we transfer ownership of the callback back to the current scope so that it
             // is dropped and cleaned up. Without this line, we would leak the Boxed callback.
-            let _: Box<Box<RenderCallback>> = unsafe {
-                Box::from_raw(callback as *mut Box<RenderCallback>)
-            };
+            unsafe { drop_fn(ptr); }
+        }
+    }
+
+    /// Pass an input callback (aka "Input Procedure") to the **AudioUnit**.
+    ///
+    /// This installs `kAudioOutputUnitProperty_SetInputCallback`, which is called whenever the
+    /// **AudioUnit** has captured some audio. The trampoline pulls the captured frames into a
+    /// buffer via `AudioUnitRender` before handing them to `f`.
+    ///
+    /// `S` must match the sample type of the **AudioUnit**'s current
+    /// [**StreamFormat**](./stream_format/struct.StreamFormat.html); returns
+    /// `Error::WrongSampleFormat` if it does not.
+    ///
+    /// Note: [**enable_io**](./struct.AudioUnit#method.enable_io) must be used to enable input
+    /// on `Scope::Input` before this will receive any audio.
+    pub fn set_input_callback<S>(&mut self, f: Option<Box<InputCallback<S>>>) -> Result<(), Error>
+        where S: Sample,
+    {
+        let callback_ptr = match f {
+            Some(callback) => {
+                let format = try!(self.stream_format());
+                try!(check_sample_format::<S>(&format));
+                let channels_per_frame = format.channels_per_frame as usize;
+                let interleaved = !format.flags.contains(LinearPcmFlags::IS_NON_INTERLEAVED);
+                let data = InputProcData {
+                    unit: self.instance,
+                    channels_per_frame: channels_per_frame,
+                    interleaved: interleaved,
+                    callback: callback,
+                };
+                Box::into_raw(Box::new(data)) as *mut libc::c_void
+            },
+            None => ptr::null_mut(),
+        };
+        let input_callback = au::AURenderCallbackStruct {
+            inputProc: Some(input_render_proc::<S>),
+            inputProcRefCon: callback_ptr
+        };
+
+        // Fix: `kAudioOutputUnitProperty_SetInputCallback` is documented as a global-scope
+        // property. It was originally installed on `Scope::Output`/`Element::Input` when this
+        // method was added; that only happened to work because `Output` and `Global` shared the
+        // same discriminant (0) before `Scope` grew an explicit `Global` variant.
+        try!(self.set_property(au::kAudioOutputUnitProperty_SetInputCallback,
+                               Scope::Global,
+                               Element::Output,
+                               Some(&input_callback)));
+
+        self.free_input_callback();
+        self.maybe_input_callback = if !callback_ptr.is_null() {
+            Some((callback_ptr, drop_input_proc_data::<S> as unsafe fn(*mut libc::c_void)))
+        } else {
+            None
+        };
+        Ok(())
+    }
+
+    /// Retrieves ownership over the input callback and drops it.
+    fn free_input_callback(&mut self) {
+        if let Some((ptr, drop_fn)) = self.maybe_input_callback.take() {
+            // Here, we transfer ownership of the callback back to the current scope so that it
+            // is dropped and cleaned up. Without this line, we would leak the Boxed callback.
+            unsafe { drop_fn(ptr); }
         }
     }
 
@@ -255,6 +543,7 @@ impl AudioUnit {
     ///
     /// **Available** in OS X v10.0 and later.
     pub fn start(&mut self) -> Result<(), Error> {
+        try!(self.initialize());
         unsafe { try_os_status!(au::AudioOutputUnitStart(self.instance)); }
         Ok(())
     }
@@ -292,10 +581,53 @@ impl AudioUnit {
     /// Return the current Stream Format for the AudioUnit.
     pub fn stream_format(&self) -> Result<StreamFormat, Error> {
         let id = au::kAudioUnitProperty_StreamFormat;
+        // Fix: `kAudioUnitProperty_StreamFormat` is scoped per Input/Output, never Global. Before
+        // `Scope` grew an explicit `Global` variant, `Output` shared `Global`'s discriminant (0),
+        // so this was silently querying the global scope instead of the output scope; it now
+        // correctly sends `Output`.
         let asbd = try!(self.get_property(id, Scope::Output, Element::Output));
         StreamFormat::from_asbd(asbd)
     }
 
+    /// Get the current I/O buffer size, in frames.
+    ///
+    /// This determines the latency and callback cadence of the render/input callbacks: smaller
+    /// values mean lower latency but more frequent callbacks.
+    pub fn buffer_frame_size(&self) -> Result<u32, Error> {
+        let id = au::kAudioDevicePropertyBufferFrameSize;
+        self.get_property(id, Scope::Global, Element::Output)
+    }
+
+    /// Set the I/O buffer size, in frames.
+    ///
+    /// See [**buffer_frame_size_range**](./struct.AudioUnit#method.buffer_frame_size_range) for
+    /// the minimum/maximum `frames` supported by the current device.
+    pub fn set_buffer_frame_size(&mut self, frames: u32) -> Result<(), Error> {
+        let id = au::kAudioDevicePropertyBufferFrameSize;
+        self.set_property(id, Scope::Global, Element::Output, Some(&frames))
+    }
+
+    /// The range of I/O buffer sizes (in frames) supported by the current device, as a
+    /// `(minimum, maximum)` pair.
+    pub fn buffer_frame_size_range(&self) -> Result<(u32, u32), Error> {
+        let id = au::kAudioDevicePropertyBufferFrameSizeRange;
+        let range: au::AudioValueRange = try!(self.get_property(id, Scope::Global, Element::Output));
+        Ok((range.mMinimum as u32, range.mMaximum as u32))
+    }
+
+    /// Bind this **AudioUnit** to the given device, rather than the system default.
+    ///
+    /// See [**device::audio_device_ids**](./device/fn.audio_device_ids.html) for obtaining an
+    /// **AudioDeviceID** to pass here.
+    pub fn set_device(&mut self, device: AudioDeviceID) -> Result<(), Error> {
+        let id = au::kAudioOutputUnitProperty_CurrentDevice;
+        // Fix: `kAudioOutputUnitProperty_CurrentDevice` is documented as a global-scope property.
+        // It was originally installed on `Scope::Output` when this method was added; that only
+        // happened to work because `Output` and `Global` shared the same discriminant (0) before
+        // `Scope` grew an explicit `Global` variant.
+        self.set_property(id, Scope::Global, Element::Output, Some(&device.0))
+    }
+
 }
 
 
@@ -304,53 +636,195 @@ impl Drop for AudioUnit {
         unsafe {
             use error;
             use std::error::Error;
-            if let Err(err) = self.stop() {
-                panic!("{:?}", err.description());
-            }
-            if let Err(err) = error::Error::from_os_status(au::AudioUnitUninitialize(self.instance)) {
-                panic!("{:?}", err.description());
+            if self.init {
+                if let Err(err) = self.stop() {
+                    panic!("{:?}", err.description());
+                }
+                if let Err(err) = error::Error::from_os_status(au::AudioUnitUninitialize(self.instance)) {
+                    panic!("{:?}", err.description());
+                }
             }
             self.free_render_callback();
+            self.free_input_callback();
         }
     }
 }
 
 
+/// The number of whole `T`s that fit in a buffer of `size` bytes, along with the byte length of
+/// exactly that many `T`s.
+///
+/// The latter is what must be passed as an out-size to a `AudioObjectGetPropertyData`/
+/// `AudioUnitGetProperty` call filling a `Vec<T>` of the returned length: if `size` were not an
+/// exact multiple of `size_of::<T>()`, passing the raw queried `size` as the out-size would let
+/// CoreAudio write past the end of the allocated buffer.
+pub(crate) fn clamped_vec_len<T>(size: u32) -> (usize, usize) {
+    let len = size as usize / mem::size_of::<T>();
+    let byte_len = len * mem::size_of::<T>();
+    (len, byte_len)
+}
+
+/// Whether a buffer list with `num_buffers` entries represents an interleaved layout for a stream
+/// with `channels_per_frame` channels. More channels than buffers means the channels are packed
+/// into a single buffer rather than split one-per-buffer.
+fn is_interleaved(channels_per_frame: usize, num_buffers: usize) -> bool {
+    channels_per_frame > num_buffers
+}
+
+/// The size, in bytes, of an `AudioBufferList` holding `num_buffers` buffers.
+///
+/// `AudioBufferList` is a variable-length struct in C (a `mNumberBuffers` count followed by that
+/// many `AudioBuffer`s), but the bindings declare `mBuffers` as a single-element array, so we have
+/// to compute the size of the extra buffers ourselves.
+fn audio_buffer_list_byte_size(num_buffers: usize) -> usize {
+    mem::size_of::<au::AudioBufferList>()
+        + num_buffers.saturating_sub(1) * mem::size_of::<au::AudioBuffer>()
+}
+
+/// Check that `S` matches `format`'s sample representation (bit depth and float-vs-integer), so
+/// that a mismatched render/input callback is rejected up front rather than silently
+/// reinterpreting the captured bytes as the wrong type.
+fn check_sample_format<S: Sample>(format: &StreamFormat) -> Result<(), Error> {
+    let bits_match = format.bits_per_channel as usize == S::bytes_per_sample() * 8;
+    let float_match = format.flags.contains(LinearPcmFlags::IS_FLOAT) == S::is_float();
+    if bits_match && float_match {
+        Ok(())
+    } else {
+        Err(Error::WrongSampleFormat)
+    }
+}
+
+/// Drop glue for a `*mut RenderProcData<S>`, monomorphized and captured as a plain function
+/// pointer at the point `S` is known so that it may be called again later from
+/// `free_render_callback`, which (being reached via `Drop`) no longer has `S` in scope.
+unsafe fn drop_render_proc_data<S>(ptr: *mut libc::c_void) {
+    let _: Box<RenderProcData<S>> = Box::from_raw(ptr as *mut RenderProcData<S>);
+}
+
+/// Drop glue for a `*mut InputProcData<S>`. See `drop_render_proc_data`.
+unsafe fn drop_input_proc_data<S>(ptr: *mut libc::c_void) {
+    let _: Box<InputProcData<S>> = Box::from_raw(ptr as *mut InputProcData<S>);
+}
+
+/// Build the `Args` for a buffer list, treating it as interleaved if there are more channels
+/// than buffers and as planar (one buffer per channel) otherwise.
+///
+/// Each buffer's length is computed from its `mDataByteSize` divided by `S`'s size, rather than
+/// assuming it is always `in_number_frames` (which is wrong for interleaved buffers, and for any
+/// sample type other than the one originally assumed).
+unsafe fn args_from_buffer_list<'b, S: Sample>(channels_per_frame: usize,
+                                               buffer_list: *mut au::AudioBufferList)
+    -> Args<'b, S>
+{
+    let num_buffers = (*buffer_list).mNumberBuffers as usize;
+    let buffers_ptr = (*buffer_list).mBuffers.as_mut_ptr();
+    let bytes_per_sample = S::bytes_per_sample();
+    let len_of = |i: usize| (*buffers_ptr.offset(i as isize)).mDataByteSize as usize / bytes_per_sample;
+    let data_of = |i: usize| (*buffers_ptr.offset(i as isize)).mData as *mut S;
+
+    if is_interleaved(channels_per_frame, num_buffers) {
+        // Interleaved: a single buffer holds `frames * channels` samples.
+        Args::Interleaved(::std::slice::from_raw_parts_mut(data_of(0), len_of(0)))
+    } else {
+        // Planar/non-interleaved: one buffer per channel.
+        let channels = (0..num_buffers)
+            .map(|i| ::std::slice::from_raw_parts_mut(data_of(i), len_of(i)))
+            .collect();
+        Args::NonInterleaved(channels)
+    }
+}
+
 /// Callback procedure that will be called each time our audio_unit requests audio.
-extern "C" fn input_proc(in_ref_con: *mut libc::c_void,
-                         _io_action_flags: *mut au::AudioUnitRenderActionFlags,
-                         _in_time_stamp: *const au::AudioTimeStamp,
-                         _in_bus_number: au::UInt32,
-                         in_number_frames: au::UInt32,
-                         io_data: *mut au::AudioBufferList) -> au::OSStatus {
-    let callback: *mut Box<RenderCallback> = in_ref_con as *mut _;
+extern "C" fn render_proc<S: Sample>(in_ref_con: *mut libc::c_void,
+                                     _io_action_flags: *mut au::AudioUnitRenderActionFlags,
+                                     _in_time_stamp: *const au::AudioTimeStamp,
+                                     _in_bus_number: au::UInt32,
+                                     in_number_frames: au::UInt32,
+                                     io_data: *mut au::AudioBufferList) -> au::OSStatus {
+    let data: *mut RenderProcData<S> = in_ref_con as *mut _;
+    unsafe {
+        let args = args_from_buffer_list((*data).channels_per_frame, io_data);
+        match (*(*data).callback)(args, in_number_frames as usize) {
+            Ok(()) => 0 as au::OSStatus,
+            Err(description) => {
+                use std::io::Write;
+                writeln!(::std::io::stderr(), "{:?}", description).unwrap();
+                AudioUnitError::NoConnection as au::OSStatus
+            },
+        }
+    }
+}
+
+
+/// Callback procedure that will be called each time our audio_unit has captured some audio.
+///
+/// Unlike the render callback, `io_data` here is null: we must pull the captured frames
+/// ourselves via `AudioUnitRender` before we can hand them to the user's callback.
+extern "C" fn input_render_proc<S: Sample>(in_ref_con: *mut libc::c_void,
+                                           io_action_flags: *mut au::AudioUnitRenderActionFlags,
+                                           in_time_stamp: *const au::AudioTimeStamp,
+                                           in_bus_number: au::UInt32,
+                                           in_number_frames: au::UInt32,
+                                           _io_data: *mut au::AudioBufferList) -> au::OSStatus {
+    let data: *mut InputProcData<S> = in_ref_con as *mut _;
     unsafe {
-        let num_channels = (*io_data).mNumberBuffers as usize;
-
-        // FIXME: We shouldn't need a Vec for this, it should probably be something like
-        // `&[&mut [f32]]` instead.
-        let mut channels: Vec<&mut [f32]> =
-            (0..num_channels)
-                .map(|i| {
-                    let slice_ptr = (*io_data).mBuffers[i].mData as *mut libc::c_float;
-                    // TODO: the size of this buffer needs to be calculated properly based on the stream format.
-                    // Currently this won't be correct in at least this case:
-                    /*
-                    stream_format::StreamFormat {
-                        sample_rate: 44100.0,
-                        audio_format: audio_format::AudioFormat::LinearPCM(Some(audio_format::LinearPCMFlag::IsFloat)),
-                        bytes_per_packet: 2 * 4,
-                        frames_per_packet: 1,
-                        bytes_per_frame: 2 * 4,
-                        channels_per_frame: 2,
-                        bits_per_channel: 32
-                    }
-                     */
-                    ::std::slice::from_raw_parts_mut(slice_ptr, in_number_frames as usize)
-                })
+        let channels_per_frame = (*data).channels_per_frame;
+        let interleaved = (*data).interleaved;
+        let bytes_per_sample = S::bytes_per_sample();
+        let num_frames = in_number_frames as usize;
+
+        // Shape the buffer list to match the negotiated `StreamFormat`: a single buffer holding
+        // all channels' samples if interleaved, or one buffer per channel if planar. Handing
+        // `AudioUnitRender` a buffer list of the wrong shape fails or produces garbage.
+        let num_buffers = if interleaved { 1 } else { channels_per_frame };
+        let channels_per_buffer = if interleaved { channels_per_frame } else { 1 };
+        let mut buffers: Vec<Vec<u8>> =
+            (0..num_buffers)
+                .map(|_| vec![0u8; num_frames * channels_per_buffer * bytes_per_sample])
                 .collect();
 
-        match (*callback)(&mut channels[..], in_number_frames as usize) {
+        // `AudioBufferList` is a variable-length struct in C (a `mNumberBuffers` count followed
+        // by that many `AudioBuffer`s), but the bindings declare `mBuffers` as a single-element
+        // array. We allocate a correctly-sized, zeroed block and write the buffer list into it
+        // ourselves so that it holds `num_buffers` buffers.
+        let list_size = audio_buffer_list_byte_size(num_buffers);
+        let mut list_bytes: Vec<u8> = vec![0; list_size];
+        let buffer_list_ptr = list_bytes.as_mut_ptr() as *mut au::AudioBufferList;
+        (*buffer_list_ptr).mNumberBuffers = num_buffers as u32;
+        let buffers_ptr = (*buffer_list_ptr).mBuffers.as_mut_ptr();
+        for (i, buffer) in buffers.iter_mut().enumerate() {
+            *buffers_ptr.offset(i as isize) = au::AudioBuffer {
+                mNumberChannels: channels_per_buffer as u32,
+                mDataByteSize: buffer.len() as u32,
+                mData: buffer.as_mut_ptr() as *mut libc::c_void,
+            };
+        }
+
+        let status = au::AudioUnitRender((*data).unit,
+                                         io_action_flags,
+                                         in_time_stamp,
+                                         in_bus_number,
+                                         in_number_frames,
+                                         buffer_list_ptr);
+        if status != 0 {
+            return status;
+        }
+
+        let args = if interleaved {
+            let buffer = &mut buffers[0];
+            let len = buffer.len() / bytes_per_sample;
+            Args::Interleaved(::std::slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut S, len))
+        } else {
+            let channels: Vec<&mut [S]> =
+                buffers.iter_mut()
+                    .map(|buffer| {
+                        ::std::slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut S, num_frames)
+                    })
+                    .collect();
+            Args::NonInterleaved(channels)
+        };
+
+        match (*(*data).callback)(args, num_frames) {
             Ok(()) => 0 as au::OSStatus,
             Err(description) => {
                 use std::io::Write;
@@ -360,3 +834,63 @@ extern "C" fn input_proc(in_ref_con: *mut libc::c_void,
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::{audio_buffer_list_byte_size, clamped_vec_len, is_interleaved, Element};
+    use std::mem;
+    use bindings::audio_unit as au;
+
+    #[test]
+    fn to_bus_number_maps_output_and_input_to_their_fixed_bus_numbers() {
+        assert_eq!(Element::Output.to_bus_number(), 0);
+        assert_eq!(Element::Input.to_bus_number(), 1);
+    }
+
+    #[test]
+    fn to_bus_number_passes_an_arbitrary_bus_through_unchanged() {
+        assert_eq!(Element::Bus(0).to_bus_number(), 0);
+        assert_eq!(Element::Bus(3).to_bus_number(), 3);
+        assert_eq!(Element::Bus(42).to_bus_number(), 42);
+    }
+
+    #[test]
+    fn is_interleaved_when_more_channels_than_buffers() {
+        // A single buffer holding all 2 channels' samples: interleaved.
+        assert!(is_interleaved(2, 1));
+    }
+
+    #[test]
+    fn is_interleaved_false_when_one_buffer_per_channel() {
+        // One buffer per channel: planar/non-interleaved.
+        assert!(!is_interleaved(2, 2));
+    }
+
+    #[test]
+    fn audio_buffer_list_byte_size_accounts_for_every_extra_buffer() {
+        let one = audio_buffer_list_byte_size(1);
+        let two = audio_buffer_list_byte_size(2);
+        let eight = audio_buffer_list_byte_size(8);
+        assert_eq!(one, mem::size_of::<au::AudioBufferList>());
+        assert_eq!(two, one + mem::size_of::<au::AudioBuffer>());
+        assert_eq!(eight, one + 7 * mem::size_of::<au::AudioBuffer>());
+    }
+
+    #[test]
+    fn clamped_vec_len_is_exact_for_an_exact_multiple() {
+        let size = 4 * mem::size_of::<u32>() as u32;
+        assert_eq!(clamped_vec_len::<u32>(size), (4, size as usize));
+    }
+
+    #[test]
+    fn clamped_vec_len_rounds_down_and_clamps_the_byte_len_for_a_non_multiple() {
+        // One byte short of holding a 5th `u32`: must report a `len` of 4 and a `byte_len` that
+        // matches exactly 4 `u32`s, never the original (too-large) `size`.
+        let size = 4 * mem::size_of::<u32>() as u32 + 1;
+        let (len, byte_len) = clamped_vec_len::<u32>(size);
+        assert_eq!(len, 4);
+        assert_eq!(byte_len, 4 * mem::size_of::<u32>());
+        assert!(byte_len < size as usize);
+    }
+}
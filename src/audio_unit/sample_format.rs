@@ -0,0 +1,37 @@
+//! A small trait used to parameterize the render and input callbacks over the sample type an
+//! **AudioUnit** has been configured to use, as determined by its **StreamFormat**.
+
+use libc;
+
+
+/// A sample type that a render or input callback may be parameterized over.
+///
+/// Implemented for the linear PCM sample types this crate currently supports: `f32`, `i16` and
+/// `i32`. Which one is appropriate depends on the **AudioUnit**'s current **StreamFormat** (its
+/// `audio_format` and `bits_per_channel`) -- it is up to the caller to select the `Sample` type
+/// that matches the format it has configured via
+/// [**set_stream_format**](../struct.AudioUnit#method.set_stream_format).
+pub trait Sample: Copy {
+    /// The number of bytes used to represent a single sample of this type.
+    fn bytes_per_sample() -> usize;
+
+    /// Whether this type represents a floating-point sample, as opposed to a signed integer one.
+    ///
+    /// Used to check `Self` against a **StreamFormat**'s `LinearPcmFlags::IS_FLOAT` flag.
+    fn is_float() -> bool;
+}
+
+impl Sample for f32 {
+    fn bytes_per_sample() -> usize { ::std::mem::size_of::<libc::c_float>() }
+    fn is_float() -> bool { true }
+}
+
+impl Sample for i16 {
+    fn bytes_per_sample() -> usize { ::std::mem::size_of::<i16>() }
+    fn is_float() -> bool { false }
+}
+
+impl Sample for i32 {
+    fn bytes_per_sample() -> usize { ::std::mem::size_of::<i32>() }
+    fn is_float() -> bool { false }
+}